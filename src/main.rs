@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fs::{create_dir_all, File};
 use std::io::{BufReader, BufWriter, BufRead, Write};
 use std::path::{Path, PathBuf};
@@ -5,48 +6,347 @@ use std::sync::Arc;
 use std::thread;
 
 use clap::Parser;
+use glob::glob;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use regex::{Regex, RegexSet};
+use regex::bytes::{Regex, RegexBuilder};
 use serde::{Serialize, Deserialize};
 
+struct RemovePattern {
+    regex: Regex,
+    source: String,
+}
+
 struct ReplacePattern {
     regex: Regex,
-    replacement: String,
+    replacement: Vec<u8>,
+    source: String,
 }
 
 struct Patterns {
-    remove: RegexSet,
+    remove: Vec<RemovePattern>,
     replace: Vec<ReplacePattern>,
 }
 
+// Per-pattern counters collected while processing one file, serialized into the `--report` JSON.
+#[derive(Serialize)]
+struct RemoveStat {
+    pattern: String,
+    lines_removed: u64,
+}
+
+#[derive(Serialize)]
+struct ReplaceStat {
+    pattern: String,
+    substitutions: u64,
+}
+
+#[derive(Serialize)]
+struct FileReport {
+    file: String,
+    lines_total: u64,
+    lines_removed: u64,
+    remove: Vec<RemoveStat>,
+    replace: Vec<ReplaceStat>,
+}
+
+#[derive(Serialize)]
+struct Report {
+    files: Vec<FileReport>,
+}
+
+// `flags` maps i/m/s/x onto RegexBuilder options; `literal` escapes the pattern first so it's matched verbatim.
+fn build_regex(pattern: &str, flags: Option<&str>, literal: bool) -> Result<Regex, String> {
+    let escaped;
+    let pattern = if literal {
+        escaped = regex::escape(pattern);
+        &escaped
+    } else {
+        pattern
+    };
+
+    let mut builder = RegexBuilder::new(pattern);
+    for flag in flags.into_iter().flat_map(|flags| flags.chars()) {
+        match flag {
+            'i' => { builder.case_insensitive(true); }
+            'm' => { builder.multi_line(true); }
+            's' => { builder.dot_matches_new_line(true); }
+            'x' => { builder.ignore_whitespace(true); }
+            _ => return Err(format!("unknown regex flag '{}'", flag)),
+        };
+    }
+
+    builder.build().map_err(|e| format!("{}", e))
+}
+
+// Catches a typo'd group number or name at load time instead of it silently vanishing at replace time.
+fn validate_capture_refs(regex: &Regex, replacement: &str, index: usize) -> Result<(), String> {
+    let bytes = replacement.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'$' {
+            i += 1;
+            continue;
+        }
+        i += 1;
+        if i >= bytes.len() {
+            break;
+        }
+        if bytes[i] == b'$' {
+            // `$$` is an escaped, literal dollar sign.
+            i += 1;
+            continue;
+        }
+
+        let braced = bytes[i] == b'{';
+        let start = if braced { i + 1 } else { i };
+        let mut end = start;
+        while end < bytes.len() && (bytes[end].is_ascii_alphanumeric() || bytes[end] == b'_') {
+            end += 1;
+        }
+
+        if braced && (end >= bytes.len() || bytes[end] != b'}') {
+            return Err(format!(
+                "replace pattern {}: unterminated '${{' in replacement",
+                index
+            ));
+        }
+
+        let name = &replacement[start..end];
+        if name.is_empty() {
+            i = end.max(i + 1);
+            continue;
+        }
+
+        if let Ok(group) = name.parse::<usize>() {
+            if group >= regex.captures_len() {
+                return Err(format!(
+                    "replace pattern {}: replacement references group ${} but the pattern only has {} group(s)",
+                    index, group, regex.captures_len() - 1
+                ));
+            }
+        } else if !regex.capture_names().any(|n| n == Some(name)) {
+            return Err(format!(
+                "replace pattern {}: replacement references unknown named group '{}'",
+                index, name
+            ));
+        }
+
+        i = if braced { end + 1 } else { end };
+    }
+
+    Ok(())
+}
+
+// Lets YAML authors write `\n`, `\t`, `\xNN`, `\u{...}` etc. instead of embedding raw control bytes.
+fn unescape_replacement(replacement: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut chars = replacement.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0u8; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => out.push(b'\n'),
+            Some('t') => out.push(b'\t'),
+            Some('r') => out.push(b'\r'),
+            Some('0') => out.push(0u8),
+            Some('\\') => out.push(b'\\'),
+            Some('x') => {
+                let hi = chars.next().ok_or("truncated \\x escape in replacement")?;
+                let lo = chars.next().ok_or("truncated \\x escape in replacement")?;
+                let byte = u8::from_str_radix(&format!("{}{}", hi, lo), 16)
+                    .map_err(|_| "invalid \\xNN escape in replacement".to_string())?;
+                out.push(byte);
+            }
+            Some('u') => {
+                if chars.next() != Some('{') {
+                    return Err("expected '{' after \\u in replacement".to_string());
+                }
+                let mut hex = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => hex.push(c),
+                        None => return Err("unterminated \\u{...} escape in replacement".to_string()),
+                    }
+                }
+                let code = u32::from_str_radix(&hex, 16)
+                    .map_err(|_| "invalid \\u{...} escape in replacement".to_string())?;
+                let ch = char::from_u32(code)
+                    .ok_or("invalid unicode code point in \\u{...} escape")?;
+                let mut buf = [0u8; 4];
+                out.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+            }
+            Some(other) => {
+                out.push(b'\\');
+                let mut buf = [0u8; 4];
+                out.extend_from_slice(other.encode_utf8(&mut buf).as_bytes());
+            }
+            None => out.push(b'\\'),
+        }
+    }
+
+    Ok(out)
+}
+
+enum TemplatePart {
+    Literal(Vec<u8>),
+    Placeholder(String),
+}
+
+// Only `{{name}}` substitution is supported, no Mustache sections/partials.
+fn parse_template(template: &str) -> Vec<TemplatePart> {
+    let placeholder = regex::Regex::new(r"\{\{\s*(\w+)\s*\}\}").unwrap();
+
+    let mut parts = Vec::new();
+    let mut last = 0;
+    for caps in placeholder.captures_iter(template) {
+        let whole = caps.get(0).unwrap();
+        if whole.start() > last {
+            parts.push(TemplatePart::Literal(template.as_bytes()[last..whole.start()].to_vec()));
+        }
+        parts.push(TemplatePart::Placeholder(caps[1].to_string()));
+        last = whole.end();
+    }
+    if last < template.len() {
+        parts.push(TemplatePart::Literal(template.as_bytes()[last..].to_vec()));
+    }
+
+    parts
+}
+
+// A placeholder with no matching capture renders as empty.
+fn render_template(parts: &[TemplatePart], captures: &HashMap<String, Vec<u8>>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for part in parts {
+        match part {
+            TemplatePart::Literal(bytes) => out.extend_from_slice(bytes),
+            TemplatePart::Placeholder(name) => {
+                if let Some(bytes) = captures.get(name) {
+                    out.extend_from_slice(bytes);
+                }
+            }
+        }
+    }
+    out
+}
+
+// Collects named captures from every replace pattern that matches; returns whether any did.
+fn collect_captures(replace: &[ReplacePattern], line: &[u8], captures: &mut HashMap<String, Vec<u8>>) -> bool {
+    let mut matched = false;
+    for ReplacePattern { regex, .. } in replace {
+        if let Some(caps) = regex.captures(line) {
+            matched = true;
+            for name in regex.capture_names().flatten() {
+                if let Some(m) = caps.name(name) {
+                    captures.insert(name.to_string(), m.as_bytes().to_vec());
+                }
+            }
+        }
+    }
+    matched
+}
+
+// Returns each matched file paired with its path relative to `input_dir`, so the output tree can mirror the input tree.
+fn discover_inputs(
+    input_dir: &Path,
+    globs: &str,
+    include: Option<&str>,
+    exclude: Option<&str>,
+) -> Vec<(PathBuf, PathBuf)> {
+    fn filters(spec: Option<&str>) -> Vec<String> {
+        spec.unwrap_or("")
+            .split(',')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+
+    let include = filters(include);
+    let exclude = filters(exclude);
+
+    let mut inputs = Vec::new();
+    for pattern in globs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let pattern = input_dir.join(pattern);
+        for entry in glob(&pattern.to_string_lossy()).expect("Invalid glob pattern") {
+            let path = entry.expect("Unable to read glob entry");
+            if !path.is_file() {
+                continue;
+            }
+
+            let relative = path.strip_prefix(input_dir).unwrap_or(&path).to_path_buf();
+            let relative_lower = relative.to_string_lossy().to_lowercase();
+
+            if !include.is_empty() && !include.iter().any(|s| relative_lower.contains(s.as_str())) {
+                continue;
+            }
+            if exclude.iter().any(|s| relative_lower.contains(s.as_str())) {
+                continue;
+            }
+
+            inputs.push((path, relative));
+        }
+    }
+
+    inputs.sort();
+    inputs.dedup();
+    inputs
+}
+
 fn parse_patterns<P>(path: P) -> Result<Patterns, String> where P: AsRef<Path> {
     let file = File::open(path).map_err(|e| format!("{}", e))?;
     let reader = BufReader::new(file);
 
+    #[derive(Serialize, Deserialize)]
+    struct StringRemovePattern {
+        regex: String,
+        flags: Option<String>,
+        #[serde(default)]
+        literal: bool,
+    }
+
     #[derive(Serialize, Deserialize)]
     struct StringReplacePattern {
         regex: String,
         replacement: String,
+        flags: Option<String>,
+        #[serde(default)]
+        literal: bool,
     }
 
     #[derive(Serialize, Deserialize)]
     struct StringPatterns {
-        remove: Vec<String>,
+        remove: Vec<StringRemovePattern>,
         replace: Vec<StringReplacePattern>,
     }
 
     let StringPatterns { remove, replace } = serde_yaml::from_reader(reader)
         .map_err(|e| format!("{}", e))?;
 
-    let remove = RegexSet::new(remove).unwrap();
+    let remove = remove
+        .into_iter()
+        .map(|StringRemovePattern { regex, flags, literal }| {
+            let source = regex.clone();
+            let regex = build_regex(&regex, flags.as_deref(), literal)?;
+            Ok(RemovePattern { regex, source })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
 
     let replace = replace
         .into_iter()
-        .map(|StringReplacePattern { regex, replacement }| {
-            let regex = Regex::new(&regex).unwrap();
-            ReplacePattern { regex, replacement }
+        .enumerate()
+        .map(|(index, StringReplacePattern { regex, replacement, flags, literal })| {
+            let source = regex.clone();
+            let regex = build_regex(&regex, flags.as_deref(), literal)?;
+            validate_capture_refs(&regex, &replacement, index)?;
+            let replacement = unescape_replacement(&replacement)?;
+            Ok(ReplacePattern { regex, replacement, source })
         })
-        .collect();
+        .collect::<Result<Vec<_>, String>>()?;
 
     Ok(Patterns { remove, replace })
 }
@@ -62,37 +362,73 @@ struct Cli {
     #[clap(short, long, default_value_t = String::from("patterns.yaml"))]
     patterns: String,
 
-    /// Comma separated list of languages to clean.
-    #[clap(short, long, default_value_t = String::from("en,de,fr,es,it,pt"))]
-    languages: String,
+    /// Comma separated list of glob patterns, resolved relative to `--input-dir`.
+    #[clap(short, long, default_value_t = String::from("**/*"))]
+    globs: String,
 
-    /// Input directory, where the raw corpora are located
+    /// Input directory that `--globs` patterns are resolved against.
     #[clap(short, long, default_value_t = String::from("raw"))]
     input_dir: String,
 
-    /// Output directory, where the processed corpora will be written to.
+    /// Output directory, where the processed files will be written to.
     #[clap(short, long, default_value_t = String::from("prepro"))]
     output_dir: String,
 
-    /// The name of the corpus, without the language extension.
-    #[clap(short, long)]
-    corpus: String,
+    /// Comma separated, case-insensitive substring filters to keep.
+    #[clap(long)]
+    include: Option<String>,
+
+    /// Comma separated, case-insensitive substring filters to drop.
+    #[clap(long)]
+    exclude: Option<String>,
+
+    /// Path to a Mustache-style template file; switches to extract mode.
+    #[clap(long)]
+    template: Option<String>,
+
+    /// In extract mode, drop lines that don't match any pattern.
+    #[clap(long)]
+    skip_unmatched: bool,
+
+    /// Path to write a per-file, per-pattern JSON statistics report to.
+    #[clap(long)]
+    report: Option<String>,
+
+    /// Collect statistics without writing any processed output.
+    #[clap(long)]
+    dry_run: bool,
 }
 
 fn main() {
     let cli = Cli::parse();
-    let corpus = cli.corpus;
-    let languages:Vec<_> = cli.languages.split(',').collect();
-    let input = PathBuf::from(cli.input_dir);
-    let output = PathBuf::from(cli.output_dir);
-    create_dir_all(&output).unwrap();
+    let input_dir = PathBuf::from(cli.input_dir);
+    let output_dir = PathBuf::from(cli.output_dir);
+    create_dir_all(&output_dir).unwrap();
+
+    let inputs = discover_inputs(&input_dir, &cli.globs, cli.include.as_deref(), cli.exclude.as_deref());
+    if inputs.is_empty() {
+        eprintln!("No input files matched '{}' in {}", cli.globs, input_dir.display());
+    }
 
     // Read patterns from YAML file.
     let patterns_path = PathBuf::from(cli.patterns);
-    let Patterns { remove, replace } = parse_patterns(patterns_path).unwrap();
+    let Patterns { remove, replace } = parse_patterns(patterns_path).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
     let remove = Arc::new(remove);
     let replace = Arc::new(replace);
 
+    // When `--template` is given, parse it once up front and share it across
+    // workers the same way the patterns are shared.
+    let template = cli.template.map(|path| {
+        let content = std::fs::read_to_string(&path).expect("Unable to read template file");
+        Arc::new(parse_template(&content))
+    });
+    let skip_unmatched = cli.skip_unmatched;
+    let dry_run = cli.dry_run;
+    let collect_stats = cli.report.is_some() || dry_run;
+
     // Progress bar
     let mpbar = MultiProgress::new();
     let style = ProgressStyle::default_spinner()
@@ -101,11 +437,15 @@ fn main() {
 
     let mut handles = Vec::new();
 
-    for language in languages {
-        let input = input.join(format!("{}.{}", corpus, language));
-        let output = output.join(format!("{}.{}", corpus, language));
+    for (input, relative) in inputs {
+        let output = output_dir.join(&relative);
+        if let Some(parent) = output.parent() {
+            create_dir_all(parent).unwrap();
+        }
         let remove = remove.clone();
         let replace = replace.clone();
+        let template = template.clone();
+        let label = relative.to_string_lossy().into_owned();
 
         let pbar = mpbar.add(ProgressBar::new_spinner());
         pbar.enable_steady_tick(100);
@@ -113,9 +453,13 @@ fn main() {
 
         let handle = thread::spawn(move || {
             let in_file = File::open(&input).expect("Unable to open file");
-            let reader = BufReader::new(in_file);
-            let out_file = File::create(&output).expect("Unable to open file");
-            let mut writer = BufWriter::new(out_file);
+            let mut reader = BufReader::new(in_file);
+            let mut writer = if dry_run {
+                None
+            } else {
+                let out_file = File::create(&output).expect("Unable to open file");
+                Some(BufWriter::new(out_file))
+            };
 
             // Initialize progress bar
             pbar.set_message(format!("Processing {}", input.display()));
@@ -124,26 +468,97 @@ fn main() {
             // Count the number of lines in the file, and the number of lines skipped.
             let mut lines_skipped = 0;
             let mut lines_total = 0;
-
-            for line in reader.lines() {
+            let mut remove_counts = vec![0u64; remove.len()];
+            let mut replace_counts = vec![0u64; replace.len()];
+
+            // Read raw bytes line-by-line instead of `BufRead::lines` so a
+            // non-UTF-8 line passes through (or gets matched/replaced) rather
+            // than panicking the whole worker thread.
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                let bytes_read = reader.read_until(b'\n', &mut line).expect("Unable to read line");
+                if bytes_read == 0 {
+                    break;
+                }
                 lines_total += 1;
                 // pbar.inc(1);
 
-                // filter all the lines that need to be removed.
-                let line = line.unwrap();
-                if remove.is_match(&line) {
+                // Strip the trailing newline (and a preceding carriage
+                // return), mirroring what `BufRead::lines` does.
+                if line.last() == Some(&b'\n') {
+                    line.pop();
+                    if line.last() == Some(&b'\r') {
+                        line.pop();
+                    }
+                }
+
+                // filter all the lines that need to be removed, attributing
+                // the skip to every remove pattern that matched (only when
+                // stats are actually requested, to avoid the extra scanning).
+                let mut removed = false;
+                for (index, p) in remove.iter().enumerate() {
+                    if p.regex.is_match(&line) {
+                        removed = true;
+                        if collect_stats {
+                            remove_counts[index] += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                if removed {
                     lines_skipped += 1;
                     continue;
                 }
 
-                // Perform substitutions on all the tuples in replace.
-                let line = replace
-                    .iter()
-                    .fold(line, |line, ReplacePattern { regex, replacement }| {
-                        regex.replace(&line, replacement).to_string()
-                    });
+                if let Some(parts) = &template {
+                    // Extract mode: render the template from this line's
+                    // named captures instead of substituting in place.
+                    let mut captures = HashMap::new();
+                    let matched = collect_captures(&replace, &line, &mut captures);
+
+                    if !matched {
+                        if skip_unmatched {
+                            lines_skipped += 1;
+                            continue;
+                        }
+                        if let Some(writer) = writer.as_mut() {
+                            writer.write_all(&line).unwrap();
+                            writer.write_all(b"\n").unwrap();
+                        }
+                        continue;
+                    }
+
+                    let rendered = render_template(parts, &captures);
+                    if let Some(writer) = writer.as_mut() {
+                        writer.write_all(&rendered).unwrap();
+                        writer.write_all(b"\n").unwrap();
+                    }
+                    continue;
+                }
+
+                // Perform substitutions on all the tuples in replace, counting
+                // a hit whenever a pattern actually matched (skipped when
+                // stats aren't requested, so each pattern only scans once).
+                let mut line = line.clone();
+                if collect_stats {
+                    for (index, ReplacePattern { regex, replacement, .. }) in replace.iter().enumerate() {
+                        if regex.is_match(&line) {
+                            replace_counts[index] += 1;
+                            line = regex.replace(&line, replacement.as_slice()).into_owned();
+                        }
+                    }
+                } else {
+                    for ReplacePattern { regex, replacement, .. } in replace.iter() {
+                        line = regex.replace(&line, replacement.as_slice()).into_owned();
+                    }
+                }
 
-                writeln!(writer, "{}", line).unwrap();
+                if let Some(writer) = writer.as_mut() {
+                    writer.write_all(&line).unwrap();
+                    writer.write_all(b"\n").unwrap();
+                }
             }
 
             // Calculate the number of lines added to the output file.
@@ -154,10 +569,40 @@ fn main() {
                 input.display(),
                 lines_remaining, lines_total, (lines_remaining as f32 / lines_total as f32) * 100.0
             ));
+
+            FileReport {
+                file: label,
+                lines_total,
+                lines_removed: lines_skipped,
+                remove: remove
+                    .iter()
+                    .zip(remove_counts)
+                    .map(|(p, lines_removed)| RemoveStat { pattern: p.source.clone(), lines_removed })
+                    .collect(),
+                replace: replace
+                    .iter()
+                    .zip(replace_counts)
+                    .map(|(p, substitutions)| ReplaceStat { pattern: p.source.clone(), substitutions })
+                    .collect(),
+            }
         });
         handles.push(handle);
     }
 
     // Wait for all progress bars to finish.
     mpbar.join().unwrap();
+
+    // Wait for every worker to finish writing before exiting, or an
+    // unjoined thread gets killed mid-write when `main` returns.
+    let files: Vec<_> = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect();
+
+    if let Some(report_path) = cli.report {
+        let report = Report { files };
+
+        let file = File::create(report_path).expect("Unable to create report file");
+        serde_json::to_writer_pretty(file, &report).expect("Unable to write report");
+    }
 }